@@ -11,19 +11,217 @@ use std::ffi::CStr;
 use std::convert::AsRef;
 use std::thread;
 
-use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use super::{Error, Event, op, Watcher};
 use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 use libc;
 
 pub struct FsEventWatcher {
   paths: cf::CFMutableArrayRef,
+  roots: HashSet<PathBuf>,
   since_when: fs::FSEventStreamEventId,
   latency: cf::CFTimeInterval,
   flags: fs::FSEventStreamCreateFlags,
   sender: Sender<Event>,
-  runloop: Option<usize>,
+  raw_sender: Option<Sender<RawEvent>>,
+  // shared with every `Handle` so the run loop can be observed and stopped
+  // from anywhere
+  lifecycle: Arc<Mutex<Lifecycle>>,
   context: Option<Box<StreamContextInfo>>,
+  // id of the most recently delivered event, for `watch_since`
+  last_event_id: Arc<Mutex<u64>>,
+  // roots watched via `RecursiveMode::NonRecursive`, filtered in the callback
+  nonrecursive_roots: HashSet<PathBuf>,
+}
+
+// the run loop's state, shared via `Arc<Mutex<_>>` so a `Handle` can
+// observe and stop it without the watcher staying put at a fixed address
+enum Lifecycle {
+  New,
+  Running(usize), // the CFRunLoopRef the stream is scheduled on, as usize
+  Stopped,
+}
+
+// a cheap, `Send`-able handle that can stop the run loop even after the
+// `FsEventWatcher` has been moved or dropped
+#[derive(Clone)]
+pub struct Handle {
+  lifecycle: Arc<Mutex<Lifecycle>>,
+}
+
+impl Handle {
+  pub fn stop(&self) {
+    let mut lifecycle = self.lifecycle.lock().unwrap();
+    if let Lifecycle::Running(runloop) = *lifecycle {
+      unsafe { cf::CFRunLoopStop(runloop as *mut libc::c_void); }
+    }
+    *lifecycle = Lifecycle::Stopped;
+  }
+}
+
+// safe: nothing here is touched from more than one thread without going
+// through `lifecycle`'s `Mutex` or a channel
+unsafe impl Send for FsEventWatcher {}
+
+// FSEvents streams are always recursive; `NonRecursive` is emulated by
+// filtering events in the callback.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecursiveMode {
+  Recursive,
+  NonRecursive,
+}
+
+// The detail FSEvents hands the callback before it's collapsed into an
+// `Event`'s coarse `op::Op`, including the id FSEvents assigns it.
+pub struct RawEvent {
+  pub path: Option<PathBuf>,
+  pub op: Result<op::Op, Error>,
+  pub event_id: u64,
+  pub flags: fse::StreamFlags,
+}
+
+// A single coalesced change, produced by `FsEventWatcher::new_debounced`.
+#[derive(Debug)]
+pub enum DebouncedEvent {
+  Create(PathBuf),
+  Write(PathBuf),
+  Chmod(PathBuf),
+  Remove(PathBuf),
+  Rename(PathBuf, PathBuf),
+  Rescan,
+  Error(Error, Option<PathBuf>),
+}
+
+struct PendingEvent {
+  op: op::Op,
+  last_update: Instant,
+}
+
+// Buffers `RawEvent`s per-path and, once `delay` has passed without a new
+// event on any path, flushes a coalesced `DebouncedEvent` for every buffered
+// path at once. Quiet time is not tracked per path: while events keep
+// arriving less than `delay` apart, nothing is flushed.
+fn debounce_worker(rx: Receiver<RawEvent>, tx: Sender<DebouncedEvent>, delay: Duration) {
+  let mut buffer: HashMap<PathBuf, PendingEvent> = HashMap::new();
+  let mut renames: Vec<(u64, PathBuf)> = Vec::new();
+
+  loop {
+    match rx.recv_timeout(delay) {
+      Ok(event) => merge_event(&mut buffer, &mut renames, &tx, event),
+      Err(RecvTimeoutError::Timeout) => flush_debounced(&mut buffer, &mut renames, &tx),
+      Err(RecvTimeoutError::Disconnected) => {
+        flush_debounced(&mut buffer, &mut renames, &tx);
+        return;
+      }
+    }
+  }
+}
+
+fn merge_event(buffer: &mut HashMap<PathBuf, PendingEvent>, renames: &mut Vec<(u64, PathBuf)>, tx: &Sender<DebouncedEvent>, event: RawEvent) {
+  let path = match event.path {
+    Some(path) => path,
+    None => return,
+  };
+
+  let op = match event.op {
+    Ok(op) => op,
+    Err(e) => {
+      tx.send(DebouncedEvent::Error(e, Some(path))).ok();
+      return;
+    }
+  };
+
+  if op.contains(op::RESCAN) {
+    tx.send(DebouncedEvent::Rescan).ok();
+    return;
+  }
+
+  if op.contains(op::RENAME) && !renames.iter().any(|&(_, ref p)| p == &path) {
+    renames.push((event.event_id, path.clone()));
+  }
+
+  // create then remove within the window cancels out
+  let cancelled_out = {
+    let pending = buffer.entry(path.clone()).or_insert_with(|| PendingEvent{ op: op::Op::empty(), last_update: Instant::now() });
+    let was_create = pending.op.contains(op::CREATE);
+    pending.op.insert(op);
+    pending.last_update = Instant::now();
+    was_create && op.contains(op::REMOVE)
+  };
+
+  if cancelled_out {
+    buffer.remove(&path);
+    renames.retain(|&(_, ref p)| p != &path);
+  }
+}
+
+fn classify(path: &PathBuf, op: op::Op) -> Option<DebouncedEvent> {
+  // create then write collapses to a single create
+  if op.contains(op::CREATE) {
+    Some(DebouncedEvent::Create(path.clone()))
+  } else if op.contains(op::REMOVE) {
+    Some(DebouncedEvent::Remove(path.clone()))
+  } else if op.contains(op::WRITE) {
+    Some(DebouncedEvent::Write(path.clone()))
+  } else if op.contains(op::CHMOD) {
+    Some(DebouncedEvent::Chmod(path.clone()))
+  } else {
+    None
+  }
+}
+
+fn flush_debounced(buffer: &mut HashMap<PathBuf, PendingEvent>, renames: &mut Vec<(u64, PathBuf)>, tx: &Sender<DebouncedEvent>) {
+  renames.sort_by_key(|&(id, _)| id);
+
+  // Adjacent event ids alone can't tell two concurrent renames apart (e.g.
+  // old_A, old_B, new_A, new_B all a step apart), so split on what's
+  // actually on disk: a rename's source path is gone, its destination
+  // exists. Pairing the two id-sorted halves positionally then recovers
+  // the right old/new matches. Falls back to adjacent pairing when that
+  // split doesn't yield two equal, non-empty halves (e.g. both paths
+  // already gone again by flush time).
+  let (gone, present): (Vec<_>, Vec<_>) = renames.drain(..).partition(|&(_, ref p)| !p.exists());
+
+  if !gone.is_empty() && gone.len() == present.len() {
+    for ((_, old_path), (_, new_path)) in gone.into_iter().zip(present.into_iter()) {
+      buffer.remove(&old_path);
+      buffer.remove(&new_path);
+      tx.send(DebouncedEvent::Rename(old_path, new_path)).ok();
+    }
+  } else {
+    *renames = gone.into_iter().chain(present.into_iter()).collect();
+    renames.sort_by_key(|&(id, _)| id);
+
+    while renames.len() >= 2 {
+      let (_, old_path) = renames.remove(0);
+      let (_, new_path) = renames.remove(0);
+      buffer.remove(&old_path);
+      buffer.remove(&new_path);
+      tx.send(DebouncedEvent::Rename(old_path, new_path)).ok();
+    }
+  }
+
+  // an odd rename left with nothing to pair against: resolve by whether
+  // the path still exists on disk
+  for (_, path) in renames.drain(..) {
+    buffer.remove(&path);
+    let event = if path.exists() {
+      DebouncedEvent::Create(path)
+    } else {
+      DebouncedEvent::Remove(path)
+    };
+    tx.send(event).ok();
+  }
+
+  for (path, pending) in buffer.drain() {
+    if let Some(event) = classify(&path, pending.op) {
+      tx.send(event).ok();
+    }
+  }
 }
 
 fn translate_flags(flags: fse::StreamFlags) -> op::Op {
@@ -43,18 +241,47 @@ fn translate_flags(flags: fse::StreamFlags) -> op::Op {
   if flags.contains(fse::ITEM_MODIFIED)  {
     ret.insert(op::WRITE);
   }
+  // buffer overflowed: the consumer must rescan this subtree itself
+  if flags.contains(fse::MUST_SCAN_SUBDIRS) || flags.contains(fse::USER_DROPPED) || flags.contains(fse::KERNEL_DROPPED) {
+    ret.insert(op::RESCAN);
+  }
   ret
 }
 
 struct StreamContextInfo {
   sender: Sender<Event>,
-  done:  Receiver<()>
+  raw_sender: Option<Sender<RawEvent>>,
+  done:  Receiver<()>,
+  last_event_id: Arc<Mutex<u64>>,
+  nonrecursive_roots: Arc<HashSet<PathBuf>>,
+}
+
+// True unless `path` falls inside one of `nonrecursive_roots` at more than
+// one level deep, in which case the deeper event must be dropped. The root
+// path itself always passes, since that's not a "deeper" event.
+//
+// Known limitation: this only looks at whether some non-recursive root is
+// an ancestor of `path`, not which watched root the event actually came
+// from. Watching `/a` recursively and `/a/b` non-recursively at the same
+// time means events for `/a/b/c/d` legitimately owed to `/a`'s subscription
+// are also dropped, since they fall more than one level deep under the
+// non-recursive root `/a/b`.
+fn passes_recursive_filter(nonrecursive_roots: &HashSet<PathBuf>, path: &Path) -> bool {
+  if nonrecursive_roots.contains(path) {
+    return true;
+  }
+  nonrecursive_roots.iter().all(|root| !path.starts_with(root) || path.parent() == Some(root.as_path()))
 }
 
 impl FsEventWatcher {
   #[inline]
   pub fn is_running(&self) -> bool {
-    self.runloop.is_some()
+    matches!(*self.lifecycle.lock().unwrap(), Lifecycle::Running(_))
+  }
+
+  // a `Handle` can outlive this watcher and still stop the run loop
+  pub fn handle(&self) -> Handle {
+    Handle { lifecycle: self.lifecycle.clone() }
   }
 
   pub fn stop(&mut self) {
@@ -62,14 +289,8 @@ impl FsEventWatcher {
       return;
     }
 
-    if let Some(runloop) = self.runloop {
-      unsafe {
-        let runloop = runloop as *mut libc::c_void;
-        cf::CFRunLoopStop(runloop);
-      }
-    }
+    self.handle().stop();
 
-    self.runloop = None;
     if let Some(ref context_info) = self.context {
       // sync done channel
       match context_info.done.recv() {
@@ -81,7 +302,12 @@ impl FsEventWatcher {
     self.context = None;
   }
 
-  fn remove_path(&mut self, source: &str) {
+  // returns true if `source` was actually being watched
+  fn remove_path(&mut self, source: &str) -> bool {
+    if !self.roots.remove(&PathBuf::from(source)) {
+      return false;
+    }
+
     unsafe {
       let cf_path = cf::str_path_to_cfstring_ref(source);
 
@@ -89,18 +315,93 @@ impl FsEventWatcher {
         let item = cf::CFArrayGetValueAtIndex(self.paths, idx);
         if cf::CFStringCompare(item, cf_path, cf::kCFCompareCaseInsensitive) == cf::kCFCompareEqualTo {
           cf::CFArrayRemoveValueAtIndex(self.paths, idx);
+          break;
         }
       }
     }
+
+    true
   }
 
+  // returns true if `source` was newly added to the root set
+  //
   // https://github.com/thibaudgg/rb-fsevent/blob/master/ext/fsevent_watch/main.c
-  fn append_path(&mut self, source: &str) {
+  fn append_path(&mut self, source: &str) -> bool {
+    if !self.roots.insert(PathBuf::from(source)) {
+      return false;
+    }
+
     unsafe {
       let cf_path = cf::str_path_to_cfstring_ref(source);
       cf::CFArrayAppendValue(self.paths, cf_path);
       cf::CFRelease(cf_path);
     }
+
+    true
+  }
+
+  // like `watch`, but lets the caller opt a root out of FSEvents' always-
+  // recursive delivery via `RecursiveMode::NonRecursive`
+  pub fn watch_with<P: AsRef<Path>>(&mut self, path: P, mode: RecursiveMode) -> Result<(), Error> {
+    let root = path.as_ref().to_path_buf();
+
+    let mode_changed = match mode {
+      RecursiveMode::NonRecursive => self.nonrecursive_roots.insert(root),
+      RecursiveMode::Recursive => self.nonrecursive_roots.remove(&root),
+    };
+
+    let is_new_root = self.append_path(&path.as_ref().to_str().unwrap());
+
+    if !is_new_root && !mode_changed {
+      return Ok(());
+    }
+
+    self.stop();
+    self.run()
+  }
+
+  // like `Watcher::new`, but coalesces bursts into `DebouncedEvent`s
+  pub fn new_debounced(tx: Sender<DebouncedEvent>, delay: Duration) -> Result<FsEventWatcher, Error> {
+    // the coarse Event stream goes unused here, but must be kept alive for
+    // the lifetime of the watcher or the callback's send() would panic
+    let (event_tx, event_rx) = channel();
+    let (raw_tx, raw_rx) = channel();
+
+    let mut watcher = try!(<FsEventWatcher as Watcher>::new(event_tx));
+    watcher.raw_events(raw_tx);
+
+    thread::spawn(move || {
+      let _event_rx = event_rx;
+      debounce_worker(raw_rx, tx, delay)
+    });
+
+    Ok(watcher)
+  }
+
+  // opts into also sending a `RawEvent` on `tx` for every `Event` delivered
+  pub fn raw_events(&mut self, tx: Sender<RawEvent>) {
+    self.raw_sender = Some(tx);
+  }
+
+  // the id of the most recent event delivered on this stream
+  pub fn last_event_id(&self) -> u64 {
+    *self.last_event_id.lock().unwrap()
+  }
+
+  // like `watch`, but replays history from `last_event_id` instead of
+  // only delivering events from now on
+  pub fn watch_since<P: AsRef<Path>>(&mut self, path: P, last_event_id: u64) -> Result<(), Error> {
+    let since_when = last_event_id as fs::FSEventStreamEventId;
+    let changed = self.since_when != since_when;
+    let is_new_root = self.append_path(&path.as_ref().to_str().unwrap());
+
+    if !changed && !is_new_root {
+      return Ok(());
+    }
+
+    self.since_when = since_when;
+    self.stop();
+    self.run()
   }
 
   pub fn run(&mut self) -> Result<(), Error> {
@@ -113,7 +414,10 @@ impl FsEventWatcher {
 
     let info = StreamContextInfo {
       sender: self.sender.clone(),
-      done: done_rx
+      raw_sender: self.raw_sender.clone(),
+      done: done_rx,
+      last_event_id: self.last_event_id.clone(),
+      nonrecursive_roots: Arc::new(self.nonrecursive_roots.clone()),
     };
 
     self.context = Some(Box::new(info));
@@ -156,7 +460,7 @@ impl FsEventWatcher {
       done_tx.send(()).ok().expect("error while signal run loop is done");
     });
     // block until runloop has been set
-    self.runloop = Some(rl_rx.recv().unwrap());
+    *self.lifecycle.lock().unwrap() = Lifecycle::Running(rl_rx.recv().unwrap());
 
     Ok(())
   }
@@ -186,8 +490,26 @@ pub unsafe extern "C" fn callback(
       .expect(format!("Unable to decode StreamFlags: {}", flags[p] as u32).as_ref());
 
     let path = PathBuf::from(from_utf8(i).ok().expect("Invalid UTF8 string."));
-    let event = Event{op: Ok(translate_flags(flag)), path: Some(path)};
 
+    if !passes_recursive_filter(&(*info).nonrecursive_roots, &path) {
+      continue;
+    }
+
+    let op = translate_flags(flag);
+
+    if let Some(ref raw_sender) = (*info).raw_sender {
+      let raw_event = RawEvent{
+        path: Some(path.clone()),
+        op: Ok(op),
+        event_id: ids[p],
+        flags: flag,
+      };
+      raw_sender.send(raw_event).ok().expect("error while sending raw event");
+    }
+
+    let event = Event{op: Ok(op), path: Some(path)};
+
+    *(*info).last_event_id.lock().unwrap() = ids[p];
     (*info).sender.send(event).ok().expect("error while sending event");
   }
 }
@@ -197,24 +519,37 @@ impl Watcher for FsEventWatcher {
   fn new(tx: Sender<Event>) -> Result<FsEventWatcher, Error> {
     Ok(FsEventWatcher {
       paths: unsafe { cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 0, &cf::kCFTypeArrayCallBacks) },
+      roots: HashSet::new(),
       since_when: fs::kFSEventStreamEventIdSinceNow,
       latency: 0.0,
       flags: fs::kFSEventStreamCreateFlagFileEvents | fs::kFSEventStreamCreateFlagNoDefer,
       sender: tx,
-      runloop: None,
+      raw_sender: None,
+      lifecycle: Arc::new(Mutex::new(Lifecycle::New)),
       context: None,
+      last_event_id: Arc::new(Mutex::new(0)),
+      nonrecursive_roots: HashSet::new(),
     })
   }
 
+  // rebuilds the stream over every watched root, so `watch(a)` then
+  // `watch(b)` keeps delivering events for both
   fn watch<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+    if !self.append_path(&path.as_ref().to_str().unwrap()) {
+      return Ok(());
+    }
+
     self.stop();
-    self.append_path(&path.as_ref().to_str().unwrap());
     self.run()
   }
 
+  // restarts the stream with whatever roots remain
   fn unwatch<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+    if !self.remove_path(&path.as_ref().to_str().unwrap()) {
+      return Ok(());
+    }
+
     self.stop();
-    self.remove_path(&path.as_ref().to_str().unwrap());
     // ignore return error: may be empty path list
     let _ = self.run();
     Ok(())
@@ -258,3 +593,127 @@ fn test_fsevent_watcher_drop() {
 
   println!("in test: {} works", file!());
 }
+
+#[test]
+fn test_translate_flags_rescan() {
+  assert!(translate_flags(fse::MUST_SCAN_SUBDIRS).contains(op::RESCAN));
+  assert!(translate_flags(fse::USER_DROPPED).contains(op::RESCAN));
+  assert!(translate_flags(fse::KERNEL_DROPPED).contains(op::RESCAN));
+  assert!(!translate_flags(fse::ITEM_CREATED).contains(op::RESCAN));
+}
+
+#[test]
+fn test_passes_recursive_filter() {
+  let mut roots = HashSet::new();
+  roots.insert(PathBuf::from("/a/b"));
+
+  // the root itself and its direct children pass
+  assert!(passes_recursive_filter(&roots, Path::new("/a/b")));
+  assert!(passes_recursive_filter(&roots, Path::new("/a/b/c")));
+  // anything deeper is filtered out
+  assert!(!passes_recursive_filter(&roots, Path::new("/a/b/c/d")));
+  // unrelated paths are unaffected
+  assert!(passes_recursive_filter(&roots, Path::new("/a/other")));
+}
+
+fn raw_event(path: &str, op: op::Op, event_id: u64) -> RawEvent {
+  RawEvent{ path: Some(PathBuf::from(path)), op: Ok(op), event_id: event_id, flags: fse::StreamFlags::empty() }
+}
+
+#[test]
+fn test_debounce_create_then_remove_cancels_out() {
+  let mut buffer = HashMap::new();
+  let mut renames = Vec::new();
+  let (tx, rx) = channel();
+
+  merge_event(&mut buffer, &mut renames, &tx, raw_event("/a", op::CREATE, 1));
+  merge_event(&mut buffer, &mut renames, &tx, raw_event("/a", op::REMOVE, 2));
+  assert!(buffer.is_empty());
+
+  flush_debounced(&mut buffer, &mut renames, &tx);
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_debounce_create_then_write_collapses_to_create() {
+  let mut buffer = HashMap::new();
+  let mut renames = Vec::new();
+  let (tx, rx) = channel();
+
+  merge_event(&mut buffer, &mut renames, &tx, raw_event("/a", op::CREATE, 1));
+  merge_event(&mut buffer, &mut renames, &tx, raw_event("/a", op::WRITE, 2));
+  flush_debounced(&mut buffer, &mut renames, &tx);
+
+  match rx.try_recv() {
+    Ok(DebouncedEvent::Create(path)) => assert_eq!(path, PathBuf::from("/a")),
+    other => panic!("expected a single Create event, got {:?}", other),
+  }
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_debounce_pairs_renames_by_event_id_not_arrival_order() {
+  let mut buffer = HashMap::new();
+  let mut renames = Vec::new();
+  let (tx, rx) = channel();
+
+  // the new path is delivered to merge_event before the old path, but
+  // carries the later event id: pairing must follow the ids, not the
+  // order merge_event was called in
+  merge_event(&mut buffer, &mut renames, &tx, raw_event("/new", op::RENAME, 11));
+  merge_event(&mut buffer, &mut renames, &tx, raw_event("/old", op::RENAME, 10));
+  flush_debounced(&mut buffer, &mut renames, &tx);
+
+  match rx.try_recv() {
+    Ok(DebouncedEvent::Rename(old, new)) => {
+      assert_eq!(old, PathBuf::from("/old"));
+      assert_eq!(new, PathBuf::from("/new"));
+    }
+    other => panic!("expected a single Rename event, got {:?}", other),
+  }
+  assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_debounce_pairs_concurrent_renames_by_filesystem_state() {
+  let mut buffer = HashMap::new();
+  let mut renames = Vec::new();
+  let (tx, rx) = channel();
+
+  let dir = std::env::temp_dir();
+  let old_a = dir.join("rsnotify_test_rename_old_a");
+  let old_b = dir.join("rsnotify_test_rename_old_b");
+  let new_a = dir.join("rsnotify_test_rename_new_a");
+  let new_b = dir.join("rsnotify_test_rename_new_b");
+  std::fs::remove_file(&old_a).ok();
+  std::fs::remove_file(&old_b).ok();
+  std::fs::File::create(&new_a).unwrap();
+  std::fs::File::create(&new_b).unwrap();
+
+  // ids interleave across the two renames, so adjacent-id pairing alone
+  // would wrongly produce Rename(old_a, old_b) and Rename(new_a, new_b)
+  merge_event(&mut buffer, &mut renames, &tx, raw_event(old_a.to_str().unwrap(), op::RENAME, 10));
+  merge_event(&mut buffer, &mut renames, &tx, raw_event(old_b.to_str().unwrap(), op::RENAME, 11));
+  merge_event(&mut buffer, &mut renames, &tx, raw_event(new_a.to_str().unwrap(), op::RENAME, 12));
+  merge_event(&mut buffer, &mut renames, &tx, raw_event(new_b.to_str().unwrap(), op::RENAME, 13));
+  flush_debounced(&mut buffer, &mut renames, &tx);
+
+  match rx.try_recv() {
+    Ok(DebouncedEvent::Rename(old, new)) => {
+      assert_eq!(old, old_a);
+      assert_eq!(new, new_a);
+    }
+    other => panic!("expected a Rename(old_a, new_a) event, got {:?}", other),
+  }
+  match rx.try_recv() {
+    Ok(DebouncedEvent::Rename(old, new)) => {
+      assert_eq!(old, old_b);
+      assert_eq!(new, new_b);
+    }
+    other => panic!("expected a Rename(old_b, new_b) event, got {:?}", other),
+  }
+  assert!(rx.try_recv().is_err());
+
+  std::fs::remove_file(&new_a).ok();
+  std::fs::remove_file(&new_b).ok();
+}
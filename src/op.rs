@@ -0,0 +1,17 @@
+use bitflags::bitflags;
+
+bitflags! {
+  // The kind(s) of change an `Event` reports. FSEvents, inotify, and friends
+  // encode far more detail than this, but every backend can be reduced to
+  // combinations of these bits.
+  pub struct Op: u32 {
+    const CHMOD  = 0b00_0001;
+    const CREATE = 0b00_0010;
+    const WRITE  = 0b00_0100;
+    const REMOVE = 0b00_1000;
+    const RENAME = 0b01_0000;
+    // The backend dropped events for a subtree and the consumer must
+    // re-scan it itself to find out what changed.
+    const RESCAN = 0b10_0000;
+  }
+}